@@ -0,0 +1,232 @@
+//! Platform-agnostic capture of a single stack frame, produced by
+//! `trace`/`trace_unsynchronized`.
+//!
+//! A `Frame` deliberately carries more than a bare instruction pointer.
+//! `ip()`, for all but the innermost frame, is a return address: it can
+//! point just past the end of the calling function, or, with inlining,
+//! into the tail of a completely different inlined call. Resolving one
+//! byte earlier reliably lands back inside the call instruction itself, so
+//! backends capture that as `symbol_address()` too. `Frame` also carries a
+//! slot for a dbghelp "inline context" on Windows, for backends that can
+//! recover one while unwinding, so `symbolize::resolve_frame` can make use
+//! of it; `trace_dbghelp` doesn't populate it today since that requires
+//! `StackWalkEx`/`STACKFRAME_EX` rather than the `StackWalk64`/`STACKFRAME64`
+//! pair it currently uses, so it's always `None` for now.
+
+use core::fmt;
+
+#[cfg(feature = "std")]
+use std::os::raw::c_void;
+#[cfg(not(feature = "std"))]
+use core::ffi::c_void;
+
+/// A single stack frame captured by `trace`/`trace_unsynchronized`.
+///
+/// See the [module level documentation](index.html) for more detail.
+pub struct Frame {
+    ip: *mut c_void,
+    symbol_address: *mut c_void,
+    #[cfg(all(windows, feature = "dbghelp"))]
+    inline_context: Option<u32>,
+}
+
+impl Frame {
+    /// Constructs a `Frame` from a bare instruction pointer, with none of
+    /// the extra backend-native context a real unwind would capture.
+    ///
+    /// This is what `symbolize::resolve` uses to call into
+    /// `symbolize::resolve_frame` without having gone through `trace`
+    /// first; it's strictly less precise than a `Frame` obtained from an
+    /// actual trace.
+    pub(crate) fn from_ip(ip: *mut c_void) -> Frame {
+        Frame {
+            ip,
+            symbol_address: ip,
+            #[cfg(all(windows, feature = "dbghelp"))]
+            inline_context: None,
+        }
+    }
+
+    /// Returns the instruction pointer of this frame.
+    ///
+    /// Note that this could be an inlined call site, and isn't necessarily
+    /// the most accurate address to hand to a symbolizer; prefer
+    /// `symbol_address` for that.
+    pub fn ip(&self) -> *mut c_void {
+        self.ip
+    }
+
+    /// Returns the address that should be used to symbolize this frame.
+    ///
+    /// This is typically `ip`, possibly adjusted to land reliably inside
+    /// the calling instruction rather than just past it.
+    pub fn symbol_address(&self) -> *mut c_void {
+        self.symbol_address
+    }
+
+    /// The dbghelp "inline context" identifying which inlined call (if
+    /// any) this frame corresponds to, captured on Windows while
+    /// unwinding. `symbolize::resolve_frame` uses this, when present, to
+    /// resolve the specific inlined call rather than the outermost
+    /// function occupying this address. Always `None` today; see the
+    /// module docs for why `trace_dbghelp` doesn't populate it yet.
+    #[cfg(all(windows, feature = "dbghelp"))]
+    pub(crate) fn inline_context(&self) -> Option<u32> {
+        self.inline_context
+    }
+}
+
+impl Clone for Frame {
+    fn clone(&self) -> Frame {
+        Frame {
+            ip: self.ip,
+            symbol_address: self.symbol_address,
+            #[cfg(all(windows, feature = "dbghelp"))]
+            inline_context: self.inline_context,
+        }
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Frame")
+            .field("ip", &self.ip)
+            .field("symbol_address", &self.symbol_address)
+            .finish()
+    }
+}
+
+/// Returns a backtrace without acquiring any locks.
+///
+/// This function is the same as `trace` except that it does not attempt to
+/// acquire a global lock before running. This is useful if you're already
+/// holding the lock or you know you're the only thread running, but
+/// otherwise this function is unsafe to use from multiple threads
+/// simultaneously.
+pub unsafe fn trace_unsynchronized<F: FnMut(&Frame) -> bool>(mut cb: F) {
+    cfg_if! {
+        if #[cfg(all(windows, feature = "dbghelp"))] {
+            trace_dbghelp(&mut cb)
+        } else if #[cfg(unix)] {
+            trace_libc(&mut cb)
+        } else {
+            let _ = &mut cb;
+        }
+    }
+}
+
+#[cfg(unix)]
+unsafe fn trace_libc(cb: &mut FnMut(&Frame) -> bool) {
+    use core::mem;
+
+    const MAX_FRAMES: usize = 256;
+    let mut buf: [*mut c_void; MAX_FRAMES] = mem::zeroed();
+    let count = libc::backtrace(buf.as_mut_ptr(), buf.len() as libc::c_int);
+    if count <= 0 {
+        return;
+    }
+
+    for &ip in buf[..count as usize].iter() {
+        // `ip` is a return address for every frame but the one libc itself
+        // captured it from, so step back into the call instruction before
+        // handing it out for symbolization.
+        let frame = Frame {
+            ip,
+            symbol_address: (ip as usize).wrapping_sub(1) as *mut c_void,
+        };
+        if !cb(&frame) {
+            return;
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "dbghelp", target_arch = "x86_64"))]
+unsafe fn trace_dbghelp(cb: &mut FnMut(&Frame) -> bool) {
+    use dbghelp;
+    use dbghelp_init;
+    use winapi::shared::minwindef::TRUE;
+    use winapi::um::dbghelp::{AddrModeFlat, STACKFRAME64, IMAGE_FILE_MACHINE_AMD64};
+    use winapi::um::processthreadsapi::GetCurrentThread;
+    use winapi::um::winnt::{RtlCaptureContext, CONTEXT};
+
+    let dbghelp = match dbghelp::dbghelp() {
+        Some(dbghelp) => dbghelp,
+        None => return,
+    };
+
+    // `StackWalk64` (and the symbol lookups it drives via its callbacks)
+    // require `SymInitializeW` to have been called for this process first.
+    let process = match dbghelp_init() {
+        Some(process) => process,
+        None => return,
+    };
+    let thread = GetCurrentThread();
+
+    let mut context: CONTEXT = core::mem::zeroed();
+    RtlCaptureContext(&mut context);
+
+    let mut frame: STACKFRAME64 = core::mem::zeroed();
+    frame.AddrPC.Offset = context.Rip;
+    frame.AddrPC.Mode = AddrModeFlat;
+    frame.AddrFrame.Offset = context.Rbp;
+    frame.AddrFrame.Mode = AddrModeFlat;
+    frame.AddrStack.Offset = context.Rsp;
+    frame.AddrStack.Mode = AddrModeFlat;
+
+    loop {
+        let ok = (dbghelp.StackWalk64)(
+            IMAGE_FILE_MACHINE_AMD64 as u32,
+            process,
+            thread,
+            &mut frame,
+            &mut context as *mut CONTEXT as *mut _,
+            None,
+            Some(core::mem::transmute(dbghelp.SymFunctionTableAccess64)),
+            Some(core::mem::transmute(dbghelp.SymGetModuleBase64)),
+            None,
+        );
+        if ok != TRUE || frame.AddrPC.Offset == 0 {
+            break;
+        }
+
+        let ip = frame.AddrPC.Offset as usize as *mut c_void;
+
+        let out = Frame {
+            ip,
+            // As in `trace_libc`, step back into the call instruction so
+            // symbolization doesn't land on whatever follows it.
+            symbol_address: (ip as usize).wrapping_sub(1) as *mut c_void,
+            // `STACKFRAME64`/`StackWalk64` carry no inline context (that's
+            // only exposed via `STACKFRAME_EX`/`StackWalkEx`), so there's
+            // nothing to capture here yet; `resolve_frame` falls back to
+            // resolving the outermost function at this address.
+            inline_context: None,
+        };
+        if !cb(&out) {
+            break;
+        }
+    }
+}
+
+// `StackWalk64` needs the initial register state for the host architecture;
+// we only special-case the common x86_64 case above today, and otherwise
+// report no frames rather than guess at a layout we haven't verified.
+#[cfg(all(windows, feature = "dbghelp", not(target_arch = "x86_64")))]
+unsafe fn trace_dbghelp(_cb: &mut FnMut(&Frame) -> bool) {}
+
+cfg_if! {
+    if #[cfg(feature = "std")] {
+        /// Returns a backtrace of the current call stack, invoking `cb` for
+        /// each `Frame` in the trace until `cb` returns `false` or the trace
+        /// is exhausted.
+        pub fn trace<F: FnMut(&Frame) -> bool>(cb: F) {
+            use lock;
+
+            let _guard = match lock::lock() {
+                Some(guard) => guard,
+                None => return,
+            };
+            unsafe { trace_unsynchronized(cb) }
+        }
+    }
+}
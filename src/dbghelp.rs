@@ -0,0 +1,228 @@
+//! A shim around `dbghelp.dll` that's loaded dynamically at runtime.
+//!
+//! `dbghelp.dll` is an optional component of most Windows installations, and
+//! backtraces are an optional feature of most programs, so linking directly
+//! against `dbghelp.dll` would force every user of this crate to ship a
+//! process that depends on it even if no backtrace is ever captured. Instead
+//! we load the DLL ourselves with `LoadLibraryA`/`GetProcAddress` and fall
+//! back to "no symbols" if it isn't present.
+//!
+//! The function pointer types declared below are hand-written duplicates of
+//! the corresponding `winapi` declarations. The `verify-winapi` feature
+//! enables compile-time assertions (further down) that keep the two in sync;
+//! without it, the `winapi` declarations of these functions are never
+//! referenced. The rest of this module still uses `winapi` for its types and
+//! for `LoadLibraryA`/`GetProcAddress`.
+
+#![allow(bad_style)]
+
+use std::mem;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::Once;
+
+use winapi::shared::basetsd::DWORD64;
+use winapi::shared::minwindef::{BOOL, DWORD, HMODULE, PDWORD};
+use winapi::shared::ntdef::PCWSTR;
+use winapi::um::dbghelp::{
+    IMAGEHLP_LINE64, PFUNCTION_TABLE_ACCESS_ROUTINE64, PGET_MODULE_BASE_ROUTINE64,
+    PREAD_PROCESS_MEMORY_ROUTINE64, PTRANSLATE_ADDRESS_ROUTINE64, STACKFRAME64, SYMBOL_INFO,
+};
+use winapi::um::libloaderapi::{GetProcAddress, LoadLibraryA};
+use winapi::um::winnt::HANDLE;
+
+macro_rules! sym {
+    (extern "system" fn $name:ident($($arg:ident: $arg_ty:ty),*) -> $ret:ty) => {
+        #[allow(non_camel_case_types)]
+        pub type $name = unsafe extern "system" fn($($arg_ty),*) -> $ret;
+    }
+}
+
+sym!(extern "system" fn SymInitializeW(
+    hProcess: HANDLE,
+    UserSearchPath: PCWSTR,
+    fInvadeProcess: BOOL
+) -> BOOL);
+sym!(extern "system" fn SymCleanup(hProcess: HANDLE) -> BOOL);
+sym!(extern "system" fn SymGetOptions() -> DWORD);
+sym!(extern "system" fn SymSetOptions(SymOptions: DWORD) -> DWORD);
+
+// Used by `backtrace::trace` to walk the stack frame-by-frame via the native
+// `STACKFRAME64` instead of just a flat list of return addresses.
+// `STACKFRAME64` carries no inline context, so frames walked this way have
+// none.
+sym!(extern "system" fn StackWalk64(
+    MachineType: DWORD,
+    hProcess: HANDLE,
+    hThread: HANDLE,
+    StackFrame: *mut STACKFRAME64,
+    // The real API takes this as an opaque `PVOID` (it's actually a
+    // `CONTEXT` on x86/x86_64, but a different type on other
+    // architectures), so we match that rather than hard-coding `CONTEXT`.
+    ContextRecord: *mut c_void,
+    ReadMemoryRoutine: PREAD_PROCESS_MEMORY_ROUTINE64,
+    FunctionTableAccessRoutine: PFUNCTION_TABLE_ACCESS_ROUTINE64,
+    GetModuleBaseRoutine: PGET_MODULE_BASE_ROUTINE64,
+    TranslateAddress: PTRANSLATE_ADDRESS_ROUTINE64
+) -> BOOL);
+sym!(extern "system" fn SymFunctionTableAccess64(hProcess: HANDLE, AddrBase: DWORD64) -> *mut std::os::raw::c_void);
+sym!(extern "system" fn SymGetModuleBase64(hProcess: HANDLE, qwAddr: DWORD64) -> DWORD64);
+
+// Used by `symbolize::resolve_frame` to turn a frame's `symbol_address`
+// (and, where available, its inline context) into a name and line number.
+sym!(extern "system" fn SymFromAddr(
+    hProcess: HANDLE,
+    Address: DWORD64,
+    Displacement: *mut DWORD64,
+    Symbol: *mut SYMBOL_INFO
+) -> BOOL);
+sym!(extern "system" fn SymGetLineFromAddr64(
+    hProcess: HANDLE,
+    dwAddr: DWORD64,
+    pdwDisplacement: PDWORD,
+    Line: *mut IMAGEHLP_LINE64
+) -> BOOL);
+sym!(extern "system" fn SymFromInlineContext(
+    hProcess: HANDLE,
+    Address: DWORD64,
+    InlineContext: DWORD,
+    Displacement: *mut DWORD64,
+    Symbol: *mut SYMBOL_INFO
+) -> BOOL);
+sym!(extern "system" fn SymGetLineFromInlineContext(
+    hProcess: HANDLE,
+    qwAddr: DWORD64,
+    InlineContext: DWORD,
+    qwModuleBaseAddress: DWORD64,
+    pdwDisplacement: PDWORD,
+    Line64: *mut IMAGEHLP_LINE64
+) -> BOOL);
+
+/// The set of `dbghelp.dll` entry points we rely on, resolved once and
+/// cached for the lifetime of the process.
+pub struct Dbghelp {
+    // Kept alive for the lifetime of the process (we never call
+    // `FreeLibrary`); not read anywhere, but keeping the handle around
+    // documents the invariant and gives us somewhere to put a `FreeLibrary`
+    // call if that ever changes.
+    #[allow(dead_code)]
+    module: HMODULE,
+    pub SymInitializeW: SymInitializeW,
+    pub SymCleanup: SymCleanup,
+    pub SymGetOptions: SymGetOptions,
+    pub SymSetOptions: SymSetOptions,
+    pub StackWalk64: StackWalk64,
+    pub SymFunctionTableAccess64: SymFunctionTableAccess64,
+    pub SymGetModuleBase64: SymGetModuleBase64,
+    pub SymFromAddr: SymFromAddr,
+    pub SymGetLineFromAddr64: SymGetLineFromAddr64,
+    // The inline-context entry points were only added to dbghelp.dll
+    // alongside Windows 8/VS2012; they may be unavailable on older systems,
+    // so callers must fall back to the non-inline-aware lookups above when
+    // these are `None`.
+    pub SymFromInlineContext: Option<SymFromInlineContext>,
+    pub SymGetLineFromInlineContext: Option<SymGetLineFromInlineContext>,
+}
+
+// The handle is never unloaded once obtained, and the function pointers are
+// plain code addresses, so it's fine to share this across threads.
+unsafe impl Send for Dbghelp {}
+unsafe impl Sync for Dbghelp {}
+
+static INIT: Once = Once::new();
+static mut DBGHELP: *const Option<Dbghelp> = ptr::null();
+
+/// Returns the lazily-loaded, cached `dbghelp.dll` bindings, or `None` if
+/// the DLL or one of the required entry points couldn't be found.
+///
+/// The module is loaded with `LoadLibraryA` the first time this is called
+/// and is never released with `FreeLibrary`; subsequent calls reuse the
+/// same handle and function pointers.
+pub fn dbghelp() -> Option<&'static Dbghelp> {
+    unsafe {
+        INIT.call_once(|| {
+            let dbghelp = load();
+            DBGHELP = Box::into_raw(Box::new(dbghelp));
+        });
+        (*DBGHELP).as_ref()
+    }
+}
+
+unsafe fn load() -> Option<Dbghelp> {
+    let module = LoadLibraryA(b"dbghelp.dll\0".as_ptr() as *const c_char);
+    if module.is_null() {
+        return None;
+    }
+
+    macro_rules! sym {
+        ($module:expr, $name:expr) => {{
+            let ptr = GetProcAddress($module, concat!($name, "\0").as_ptr() as *const c_char);
+            if ptr.is_null() {
+                return None;
+            }
+            mem::transmute::<*const c_void, _>(ptr as *const c_void)
+        }};
+    }
+
+    macro_rules! optional_sym {
+        ($module:expr, $name:expr) => {{
+            let ptr = GetProcAddress($module, concat!($name, "\0").as_ptr() as *const c_char);
+            if ptr.is_null() {
+                None
+            } else {
+                Some(mem::transmute::<*const c_void, _>(ptr as *const c_void))
+            }
+        }};
+    }
+
+    Some(Dbghelp {
+        module,
+        SymInitializeW: sym!(module, "SymInitializeW"),
+        SymCleanup: sym!(module, "SymCleanup"),
+        SymGetOptions: sym!(module, "SymGetOptions"),
+        SymSetOptions: sym!(module, "SymSetOptions"),
+        StackWalk64: sym!(module, "StackWalk64"),
+        SymFunctionTableAccess64: sym!(module, "SymFunctionTableAccess64"),
+        SymGetModuleBase64: sym!(module, "SymGetModuleBase64"),
+        SymFromAddr: sym!(module, "SymFromAddr"),
+        SymGetLineFromAddr64: sym!(module, "SymGetLineFromAddr64"),
+        SymFromInlineContext: optional_sym!(module, "SymFromInlineContext"),
+        SymGetLineFromInlineContext: optional_sym!(module, "SymGetLineFromInlineContext"),
+    })
+}
+
+#[cfg(feature = "verify-winapi")]
+mod verify {
+    //! Compile-time assertions that our hand-written function pointer types
+    //! above agree with the real `winapi` declarations. This module is only
+    //! compiled when the `verify-winapi` feature is enabled (e.g. by CI), so
+    //! the normal build doesn't need a `winapi` dependency on these symbols
+    //! beyond what's already pulled in for the rest of the Windows backend.
+
+    macro_rules! check {
+        ($name:ident: $winapi_path:path) => {
+            #[allow(dead_code)]
+            fn $name() {
+                let _: super::$name = $winapi_path as super::$name;
+            }
+        };
+    }
+
+    check!(SymInitializeW: winapi::um::dbghelp::SymInitializeW);
+    check!(SymCleanup: winapi::um::dbghelp::SymCleanup);
+    check!(StackWalk64: winapi::um::dbghelp::StackWalk64);
+    check!(SymFunctionTableAccess64: winapi::um::dbghelp::SymFunctionTableAccess64);
+    check!(SymGetModuleBase64: winapi::um::dbghelp::SymGetModuleBase64);
+
+    // `SymGetOptions`/`SymSetOptions` aren't present in `winapi`'s `dbghelp`
+    // module, which is exactly why we declare them ourselves above; there's
+    // nothing to check them against. `SymFromAddr`/`SymGetLineFromAddr64`
+    // are real dbghelp.dll exports that we look up by name at runtime, but
+    // `winapi` only binds their wide (`W`-suffixed) counterparts, which use
+    // an entirely different `SYMBOL_INFOW`/`IMAGEHLP_LINEW64` layout rather
+    // than just a renamed version of ours, so there's nothing of the right
+    // shape to check them against either. The inline-context entry points
+    // (`SymFromInlineContext`/`SymGetLineFromInlineContext`) are loaded as
+    // `Option`s above precisely because they're missing on some systems and
+    // in some `winapi` versions, so they're left unchecked here too.
+}
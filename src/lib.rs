@@ -111,10 +111,13 @@ cfg_if! {
 #[macro_use]
 mod dylib;
 
+#[cfg(all(windows, feature = "dbghelp"))]
+mod dbghelp;
+
 pub use backtrace::{trace_unsynchronized, Frame};
 mod backtrace;
 
-pub use symbolize::{resolve_unsynchronized, Symbol, SymbolName};
+pub use symbolize::{resolve_unsynchronized, resolve_frame_unsynchronized, Symbol, SymbolName};
 mod symbolize;
 
 pub use types::BytesOrWideString;
@@ -123,7 +126,7 @@ mod types;
 cfg_if! {
     if #[cfg(feature = "std")] {
         pub use backtrace::trace;
-        pub use symbolize::resolve;
+        pub use symbolize::{resolve, resolve_frame};
         pub use capture::{Backtrace, BacktraceFrame, BacktraceSymbol};
         mod capture;
     }
@@ -180,78 +183,135 @@ mod lock {
 }
 
 #[cfg(all(windows, feature = "dbghelp"))]
-struct Cleanup {
-    handle: winapi::um::winnt::HANDLE,
-    opts: winapi::shared::minwindef::DWORD,
+#[derive(Clone, Copy)]
+enum DbghelpState {
+    Uninit,
+    Initialized {
+        handle: winapi::um::winnt::HANDLE,
+        opts: winapi::shared::minwindef::DWORD,
+    },
+    Unavailable,
 }
 
 #[cfg(all(windows, feature = "dbghelp"))]
-unsafe fn dbghelp_init() -> Option<Cleanup> {
-    use winapi::shared::minwindef;
-    use winapi::um::{dbghelp, processthreadsapi};
-
-    use std::sync::{Mutex, Once, ONCE_INIT};
+fn dbghelp_state() -> &'static std::sync::Mutex<DbghelpState> {
     use std::boxed::Box;
+    use std::sync::{Mutex, Once, ONCE_INIT};
 
-    // Initializing symbols has significant overhead, but initializing only once
-    // without cleanup causes problems for external sources. For example, the
-    // standard library checks the result of SymInitializeW (which returns an
-    // error if attempting to initialize twice) and in the event of an error,
-    // will not print a backtrace on panic. Presumably, external debuggers may
-    // have similar issues.
-    //
-    // As a compromise, we'll keep track of the number of internal initialization
-    // requests within a single API call in order to minimize the number of
-    // init/cleanup cycles.
-    static mut REF_COUNT: *mut Mutex<usize> = 0 as *mut _;
-    static mut INIT: Once = ONCE_INIT;
-
-    INIT.call_once(|| {
-        REF_COUNT = Box::into_raw(Box::new(Mutex::new(0)));
-    });
+    static mut STATE: *mut Mutex<DbghelpState> = 0 as *mut _;
+    static INIT: Once = ONCE_INIT;
 
-    // Not sure why these are missing in winapi
-    const SYMOPT_DEFERRED_LOADS: minwindef::DWORD = 0x00000004;
-    extern "system" {
-        fn SymGetOptions() -> minwindef::DWORD;
-        fn SymSetOptions(options: minwindef::DWORD);
+    unsafe {
+        INIT.call_once(|| {
+            STATE = Box::into_raw(Box::new(Mutex::new(DbghelpState::Uninit)));
+        });
+        &*STATE
     }
+}
 
-    impl Drop for Cleanup {
-        fn drop(&mut self) {
-            unsafe {
-                let mut ref_count_guard = (&*REF_COUNT).lock().unwrap();
-                *ref_count_guard -= 1;
-
-                if *ref_count_guard == 0 {
-                    dbghelp::SymCleanup(self.handle);
-                    SymSetOptions(self.opts);
-                }
-            }
-        }
-    }
+// Initializing symbols has significant overhead (in particular
+// `SymInitializeW` can trigger a deferred-symbol reload), so once symbols
+// are successfully initialized for this process we keep them cached and
+// initialized for the rest of the process's lifetime rather than tearing
+// them down between calls, as `Backtrace::new()`/`resolve()` may be called
+// many times. Programs that cooperate with an external debugger or the
+// standard library's own panic backtrace should be unaffected since we
+// never call `SymCleanup` unless asked to via `clear_symbol_cache`.
+//
+// Only a missing `dbghelp.dll` is treated as a permanent `Unavailable`
+// state; a failed `SymInitializeW` call is retried on the next call
+// instead, since that can fail transiently (e.g. racing another library's
+// own initialization at startup) rather than for the rest of the process.
+#[cfg(all(windows, feature = "dbghelp"))]
+unsafe fn dbghelp_init() -> Option<winapi::um::winnt::HANDLE> {
+    use winapi::shared::minwindef;
+    use winapi::um::processthreadsapi;
 
-    let opts = SymGetOptions();
-    let handle = processthreadsapi::GetCurrentProcess();
+    use dbghelp;
 
-    let mut ref_count_guard = (&*REF_COUNT).lock().unwrap();
+    let mut state = dbghelp_state().lock().unwrap();
 
-    if *ref_count_guard > 0 {
-        *ref_count_guard += 1;
-        return Some(Cleanup { handle, opts });
+    match *state {
+        DbghelpState::Initialized { handle, .. } => return Some(handle),
+        DbghelpState::Unavailable => return None,
+        DbghelpState::Uninit => {}
     }
 
-    SymSetOptions(opts | SYMOPT_DEFERRED_LOADS);
+    // dbghelp.dll may not be present on this system (it's an optional
+    // component), in which case we have no symbols to offer.
+    let dbghelp = match dbghelp::dbghelp() {
+        Some(dbghelp) => dbghelp,
+        None => {
+            *state = DbghelpState::Unavailable;
+            return None;
+        }
+    };
+
+    // Not sure why these are missing in winapi
+    const SYMOPT_DEFERRED_LOADS: minwindef::DWORD = 0x00000004;
+
+    let opts = (dbghelp.SymGetOptions)();
+    let handle = processthreadsapi::GetCurrentProcess();
 
-    let ret = dbghelp::SymInitializeW(handle,
-                                      0 as *mut _,
-                                      minwindef::TRUE);
+    (dbghelp.SymSetOptions)(opts | SYMOPT_DEFERRED_LOADS);
+
+    let ret = (dbghelp.SymInitializeW)(handle,
+                                       std::ptr::null(),
+                                       minwindef::TRUE);
 
     if ret != minwindef::TRUE {
-        // Symbols may have been initialized by another library or an external debugger
+        // Symbols may have been initialized by another library or an
+        // external debugger, which can be a transient race at process
+        // startup rather than a permanent condition, so leave `state` as
+        // `Uninit` (rather than `Unavailable`) and retry on the next call
+        // instead of disabling symbolication for the rest of the process.
         None
     } else {
-        *ref_count_guard += 1;
-        Some(Cleanup { handle, opts })
+        *state = DbghelpState::Initialized { handle, opts };
+        Some(handle)
+    }
+}
+
+/// Releases the cached `dbghelp` symbol state for this process.
+///
+/// By default, once symbols are successfully initialized they're kept
+/// loaded and cached for the lifetime of the process, since re-running
+/// `SymInitializeW` on every `Backtrace::new()`/`resolve()` is expensive.
+/// Most programs should never need to call this. It's provided for
+/// long-running processes that want to release the loaded module/PDB state
+/// to reclaim memory, or that need to hand control of `SymInitialize` back
+/// to another library or an external debugger.
+///
+/// After calling this, the next backtrace or symbol resolution will
+/// transparently re-initialize symbols as needed.
+///
+/// This is serialized against `Backtrace::new()`/`trace()`/`resolve()` via
+/// the same global lock they take: another thread actively symbolizing a
+/// frame holds that lock for the duration of its dbghelp calls, so
+/// `SymCleanup` here can never run concurrently with a live lookup. If
+/// this is (erroneously) called reentrantly from within that callback on
+/// the same thread, the lock is already held and this becomes a no-op
+/// rather than tearing symbols down out from under the caller.
+#[cfg(all(windows, feature = "dbghelp"))]
+pub fn clear_symbol_cache() {
+    use winapi::um::processthreadsapi;
+
+    use dbghelp;
+    use lock;
+
+    let _guard = match lock::lock() {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    unsafe {
+        let mut state = dbghelp_state().lock().unwrap();
+        if let DbghelpState::Initialized { opts, .. } = *state {
+            if let Some(dbghelp) = dbghelp::dbghelp() {
+                (dbghelp.SymCleanup)(processthreadsapi::GetCurrentProcess());
+                (dbghelp.SymSetOptions)(opts);
+            }
+        }
+        *state = DbghelpState::Uninit;
     }
 }
\ No newline at end of file
@@ -0,0 +1,267 @@
+//! Support for symbolizing a `Frame` (or a bare instruction pointer) into
+//! human-readable name/file/line information.
+//!
+//! `resolve_frame`/`resolve_frame_unsynchronized` are the primitive
+//! operations here: they're handed a whole `backtrace::Frame`, which may
+//! carry backend-native context (a `symbol_address` adjusted away from a
+//! raw return address, or, on Windows, a dbghelp inline context) that lets
+//! the platform backend produce a more accurate result than resolving a
+//! bare address ever could, especially for frames adjacent to inlined
+//! calls or sitting right at a function boundary. `resolve`/
+//! `resolve_unsynchronized` remain for callers that only have an address
+//! (e.g. one read back out of a serialized backtrace); they're thin
+//! wrappers that build the minimal `Frame` info the bare address allows
+//! and hand it to `resolve_frame`.
+
+use core::fmt;
+use rustc_demangle::{try_demangle, Demangle};
+
+use backtrace::Frame;
+
+#[cfg(feature = "std")]
+use std::os::raw::c_void;
+#[cfg(not(feature = "std"))]
+use core::ffi::c_void;
+
+/// A type representing the demangled name of a function.
+///
+/// This is used as the return value of `Symbol::name` and subsequently
+/// `fmt::Display` is implemented for this structure. It's recommended to
+/// use this via the `Display` trait, but if more granular control is
+/// desired the underlying bytes are also exposed.
+pub struct SymbolName<'a> {
+    bytes: &'a [u8],
+    demangled: Option<Demangle<'a>>,
+}
+
+impl<'a> SymbolName<'a> {
+    /// Creates a new symbol name from the raw underlying bytes.
+    pub fn new(bytes: &'a [u8]) -> SymbolName<'a> {
+        let str_bytes = core::str::from_utf8(bytes).ok();
+        let demangled = str_bytes.and_then(|s| try_demangle(s).ok());
+
+        SymbolName {
+            bytes,
+            demangled,
+        }
+    }
+
+    /// Returns the raw (mangled) bytes that make up this symbol.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+}
+
+impl<'a> fmt::Display for SymbolName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref d) = self.demangled {
+            fmt::Display::fmt(d, f)
+        } else if let Ok(s) = core::str::from_utf8(self.bytes) {
+            f.write_str(s)
+        } else {
+            f.write_str("<unknown>")
+        }
+    }
+}
+
+/// A type representing the resolution of a symbol's information.
+///
+/// This is the type yielded by `resolve`/`resolve_frame` to the provided
+/// closure. See the module docs for why the `resolve_frame` family
+/// generally produces more accurate results than resolving a bare address.
+pub struct Symbol {
+    name: Option<&'static [u8]>,
+    addr: Option<*mut c_void>,
+    filename: Option<&'static str>,
+    lineno: Option<u32>,
+}
+
+impl Symbol {
+    /// Returns the name of this function.
+    pub fn name(&self) -> Option<SymbolName> {
+        self.name.map(SymbolName::new)
+    }
+
+    /// Returns the starting address of this function.
+    pub fn addr(&self) -> Option<*mut c_void> {
+        self.addr
+    }
+
+    /// Returns the file name of the source file for the line that this
+    /// symbol is currently pointing to.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename
+    }
+
+    /// Returns the line number for the line that this symbol is currently
+    /// pointing to.
+    pub fn lineno(&self) -> Option<u32> {
+        self.lineno
+    }
+}
+
+/// Resolve a `Frame` captured by `backtrace::trace` to a `Symbol`,
+/// invoking `cb` with the result. Currently this always resolves to at
+/// most a single symbol per frame, even for a frame that represents an
+/// inlined call.
+///
+/// Unlike `resolve`, this is handed the whole `Frame`, so backends can
+/// use whatever extra context they captured while unwinding (the frame's
+/// `symbol_address`, and, on Windows, its dbghelp inline context) to
+/// produce more accurate results than resolving `frame.ip()` alone would.
+#[cfg(feature = "std")]
+pub fn resolve_frame<F: FnMut(&Symbol)>(frame: &Frame, cb: F) {
+    // Like `trace`, serialize access to the symbolizer so we don't trip
+    // over other threads also resolving frames at the same time.
+    use lock;
+
+    let _guard = match lock::lock() {
+        Some(guard) => guard,
+        None => return,
+    };
+    unsafe { resolve_frame_unsynchronized(frame, cb) }
+}
+
+/// Same as `resolve_frame`, only unsafe as it doesn't have as strict
+/// thread-safety guarantees.
+///
+/// See the caveats of `backtrace::trace_unsynchronized` for how this
+/// function is not safe in general.
+pub unsafe fn resolve_frame_unsynchronized<F: FnMut(&Symbol)>(frame: &Frame, mut cb: F) {
+    resolve_imp(frame, &mut cb)
+}
+
+/// Resolve an address to a `Symbol`, invoking `cb` once per symbol
+/// resolved.
+///
+/// This is a thin wrapper around `resolve_frame`: it builds the minimal
+/// `Frame` a bare address allows (no backend-native context, so
+/// `symbol_address()` is just `addr` itself) and resolves that. Prefer
+/// `resolve_frame` whenever a real `Frame` from `backtrace::trace` is
+/// available, since it can produce more accurate results.
+#[cfg(feature = "std")]
+pub fn resolve<F: FnMut(&Symbol)>(addr: *mut c_void, cb: F) {
+    resolve_frame(&Frame::from_ip(addr), cb)
+}
+
+/// Same as `resolve`, only unsafe as it doesn't have as strict
+/// thread-safety guarantees.
+pub unsafe fn resolve_unsynchronized<F: FnMut(&Symbol)>(addr: *mut c_void, cb: F) {
+    resolve_frame_unsynchronized(&Frame::from_ip(addr), cb)
+}
+
+#[cfg(unix)]
+unsafe fn resolve_imp(frame: &Frame, cb: &mut FnMut(&Symbol)) {
+    use libc::{dladdr, Dl_info};
+    use core::mem;
+
+    let addr = frame.symbol_address();
+    let mut info: Dl_info = mem::zeroed();
+    if dladdr(addr as *const _, &mut info) == 0 {
+        return;
+    }
+
+    let name = if info.dli_sname.is_null() {
+        None
+    } else {
+        Some(::std::ffi::CStr::from_ptr(info.dli_sname).to_bytes())
+    };
+    let name: Option<&'static [u8]> = mem::transmute(name);
+
+    cb(&Symbol {
+        name,
+        addr: if info.dli_saddr.is_null() {
+            None
+        } else {
+            Some(info.dli_saddr as *mut c_void)
+        },
+        filename: None,
+        lineno: None,
+    });
+}
+
+#[cfg(all(windows, feature = "dbghelp"))]
+unsafe fn resolve_imp(frame: &Frame, cb: &mut FnMut(&Symbol)) {
+    use core::mem;
+    use dbghelp;
+    use dbghelp_init;
+    use winapi::shared::minwindef::TRUE;
+    use winapi::um::dbghelp::{SYMBOL_INFO, IMAGEHLP_LINE64, MAX_SYM_NAME};
+
+    let dbghelp = match dbghelp::dbghelp() {
+        Some(dbghelp) => dbghelp,
+        None => return,
+    };
+
+    // `SymFromAddr`/`SymGetLineFromAddr64` (and their inline-context
+    // counterparts) require `SymInitializeW` to have been called for this
+    // process first, same as `StackWalk64` in `backtrace::trace_dbghelp`.
+    let process = match dbghelp_init() {
+        Some(process) => process,
+        None => return,
+    };
+    let addr = frame.symbol_address() as u64;
+
+    const BUF_SIZE: usize = mem::size_of::<SYMBOL_INFO>() + MAX_SYM_NAME as usize;
+    let mut buf = [0u8; BUF_SIZE];
+    let info = &mut *(buf.as_mut_ptr() as *mut SYMBOL_INFO);
+    info.SizeOfStruct = mem::size_of::<SYMBOL_INFO>() as u32;
+    info.MaxNameLen = MAX_SYM_NAME;
+
+    let mut displacement = 0u64;
+
+    // When dbghelp reports an inline context for this frame and we have
+    // the matching entry points, resolve the specific inlined call instead
+    // of the outermost (non-inlined) function occupying this address.
+    let found = match (frame.inline_context(), dbghelp.SymFromInlineContext) {
+        (Some(inline_context), Some(sym_from_inline_context)) => {
+            sym_from_inline_context(process, addr, inline_context, &mut displacement, info)
+        }
+        _ => (dbghelp.SymFromAddr)(process, addr, &mut displacement, info),
+    };
+    if found != TRUE {
+        return;
+    }
+
+    let name_ptr = info.Name.as_ptr() as *const u8;
+    let name_len = info.NameLen as usize;
+    let name: &'static [u8] = mem::transmute(core::slice::from_raw_parts(name_ptr, name_len));
+
+    let mut line_displacement = 0u32;
+    let mut line: IMAGEHLP_LINE64 = mem::zeroed();
+    line.SizeOfStruct = mem::size_of::<IMAGEHLP_LINE64>() as u32;
+
+    let have_line = match (frame.inline_context(), dbghelp.SymGetLineFromInlineContext) {
+        (Some(inline_context), Some(sym_get_line_from_inline_context)) => {
+            sym_get_line_from_inline_context(
+                process,
+                addr,
+                inline_context,
+                0,
+                &mut line_displacement,
+                &mut line,
+            )
+        }
+        _ => (dbghelp.SymGetLineFromAddr64)(process, addr, &mut line_displacement, &mut line),
+    } == TRUE;
+
+    let filename: Option<&'static str> = if have_line && !line.FileName.is_null() {
+        let cstr = ::std::ffi::CStr::from_ptr(line.FileName as *const i8);
+        cstr.to_str().ok().map(|s| mem::transmute::<&str, &'static str>(s))
+    } else {
+        None
+    };
+
+    cb(&Symbol {
+        name: Some(name),
+        // `info.Address` is the address dbghelp actually resolved the name
+        // to (the start of the function/inlined call), which is more useful
+        // to callers than echoing back the lookup address they gave us.
+        addr: Some(info.Address as usize as *mut c_void),
+        filename,
+        lineno: if have_line { Some(line.LineNumber) } else { None },
+    });
+}
+
+#[cfg(not(any(unix, all(windows, feature = "dbghelp"))))]
+unsafe fn resolve_imp(_frame: &Frame, _cb: &mut FnMut(&Symbol)) {}